@@ -3,12 +3,85 @@
 // Stephen Marz
 // 10 March 2020
 
-use crate::{page::{zalloc, PAGE_SIZE},
+use crate::{page::{zalloc, dealloc, PAGE_SIZE},
 			kmem::{kmalloc, kfree},
             virtio::{MmioOffsets, Queue, VIRTIO_RING_SIZE, StatusField, Descriptor}};
 use alloc::collections::VecDeque;
 use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
 use crate::virtio;
+use crate::process;
+
+// Sentinel meaning "nobody is blocked on this descriptor".
+const NO_WAITER: u16 = u16::MAX;
+
+// Abstracts how the block driver maps queue/buffer memory to an address
+// the device's DMA engine can use. Today the kernel identity-maps all of
+// RAM, so virtual and physical addresses are numerically identical, but
+// routing every descriptor `addr` and the QueuePfn write through this
+// trait means the driver keeps working once I/O buffers live behind a
+// real page table instead of relying on that identity mapping.
+pub trait BlockHal {
+	// Allocate `pages` physically-contiguous, zeroed pages, returning both
+	// the virtual address the driver can dereference and the physical
+	// address to hand the device.
+	fn dma_alloc(pages: usize) -> (*mut u8, usize);
+	// Free memory returned by a prior dma_alloc.
+	fn dma_dealloc(virt: *mut u8);
+	fn phys_to_virt(phys: usize) -> *mut u8;
+	fn virt_to_phys(virt: *const u8) -> usize;
+}
+
+// The only HAL we have today: RAM is identity-mapped, so this is a no-op
+// translation layer backed by the ordinary page/heap allocators.
+pub struct IdentityHal;
+
+impl BlockHal for IdentityHal {
+	fn dma_alloc(pages: usize) -> (*mut u8, usize) {
+		let virt = zalloc(pages) as *mut u8;
+		(virt, virt as usize)
+	}
+
+	fn dma_dealloc(virt: *mut u8) {
+		dealloc(virt);
+	}
+
+	fn phys_to_virt(phys: usize) -> *mut u8 {
+		phys as *mut u8
+	}
+
+	fn virt_to_phys(virt: *const u8) -> usize {
+		virt as usize
+	}
+}
+
+// The HAL implementation the driver is wired up to. Swapping this alias
+// (or threading a type parameter through BlockDevice, if more than one HAL
+// is ever needed at once) is the only change required once buffers stop
+// being identity-mapped.
+type ActiveHal = IdentityHal;
+
+// Errors a caller of the blocking/async block API can see. `IoError` and
+// `Unsupported` map the device's status byte; `Unsupported` is also
+// returned locally by discard/write_zeroes when their feature bit wasn't
+// negotiated, and `IoError` when the block device list can't be reached.
+// `ReadOnly` is caught locally before we ever submit a write, discard or
+// write-zeroes request to a read-only device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+	IoError,
+	Unsupported,
+	ReadOnly,
+}
+
+impl BlockError {
+	fn from_status(status: u8) -> Self {
+		match status {
+			VIRTIO_BLK_S_UNSUPP => BlockError::Unsupported,
+			_ => BlockError::IoError,
+		}
+	}
+}
 
 #[repr(C)]
 pub struct Geometry {
@@ -18,6 +91,7 @@ pub struct Geometry {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Topology {
 	physical_block_exp: u8,
 	alignment_offset:   u8,
@@ -72,13 +146,74 @@ pub struct Request {
 	data: Data,
 	status: Status,
 	head: u16,
+	// True if `data.data` was allocated by us (e.g. a discard/write_zeroes
+	// segment buffer) and needs to be kfree'd alongside the Request. Reads
+	// and writes point data.data at the caller's own buffer, so they leave
+	// this false.
+	owns_data: bool,
+}
+
+// A single segment of a VIRTIO_BLK_T_DISCARD or VIRTIO_BLK_T_WRITE_ZEROES
+// request, as laid out by the virtio spec.
+#[repr(C)]
+pub struct DiscardWriteZeroes {
+	sector: u64,
+	num_sectors: u32,
+	flags: u32,
 }
 
+// Bit 0 of a DiscardWriteZeroes segment's flags: request the blocks be
+// unmapped rather than merely zeroed. Only meaningful for WRITE_ZEROES.
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
 // Internal block device structure
 pub struct BlockDevice {
     queue: *mut Queue,
     dev: *mut u32,
-	idx:   u16,
+	// The next used-ring index to reap (one past the last one we've reaped).
+	// We walk forward from here to the device's current used.idx every time
+	// we're notified of completions.
+	ack_used_idx: u16,
+	// Outstanding requests, keyed by the head descriptor index they were
+	// submitted under. This lets the completion reaper turn a used-ring
+	// entry back into the Request it needs to inspect and free.
+	requests: [*mut Request; VIRTIO_RING_SIZE],
+	// Free descriptor list. desc_next[i] is the descriptor that follows i
+	// in the free chain; free_head is the next descriptor alloc_desc will
+	// hand out, and num_free lets us refuse to allocate once the ring is
+	// fully in flight.
+	desc_next: [u16; VIRTIO_RING_SIZE],
+	free_head:  u16,
+	num_free:   u16,
+	// Processes parked in `reserve_descs` waiting for enough descriptors to
+	// free up. `free_desc` pops and wakes one every time it returns a
+	// descriptor to the free list; the waiter re-checks `num_free` itself
+	// once it runs again, so waking it before its reservation is actually
+	// satisfiable just costs it an extra lap through the retry loop.
+	desc_waiters: VecDeque<u16>,
+	// Blocking I/O bookkeeping, both keyed by head descriptor index: the
+	// pid to wake once a request completes (or NO_WAITER for async
+	// requests nobody is blocked on), and the status byte the reaper left
+	// behind for the waiter to pick up once it's rescheduled.
+	waiting: [u16; VIRTIO_RING_SIZE],
+	results: [u8; VIRTIO_RING_SIZE],
+	// Whether VIRTIO_BLK_F_RO was offered by the device. We still mask the
+	// bit out of GuestFeatures (we don't implement anything differently
+	// based on it being negotiated), but we remember it so `write` can
+	// refuse instead of issuing a doomed request.
+	read_only: bool,
+	// The feature subset we actually negotiated with the device (what we
+	// wrote to GuestFeatures), so callers can check a bit before relying
+	// on it.
+	features: u32,
+	// Pointer to the block device's config space, which starts at MMIO
+	// offset 0x100. Used to read device limits like max_discard_sector.
+	config: *const Config,
+	// Geometry parsed out of config space at setup time, so callers don't
+	// have to assume 512-byte sectors or know where config space lives.
+	capacity: u64,
+	blk_size: u32,
+	topology: Topology,
 }
 
 // Type values
@@ -129,11 +264,12 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 			// 2. Set ACKNOWLEDGE status bit
 			ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
 			// 3. Set the DRIVER status bit
-			status_bits |= StatusField::DriverOk.val32();
+			status_bits |= StatusField::Driver.val32();
 			ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
 			// 4. Read device feature bits, write subset of feature bits understood by OS and driver
 			//    to the device.
 			let host_features = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+			let read_only = host_features & (1 << VIRTIO_BLK_F_RO) != 0;
 			let guest_features = host_features & !(1 << VIRTIO_BLK_F_RO);
 			ptr.add(MmioOffsets::GuestFeatures.scale32()).write_volatile(guest_features);
 			// 5. Set the FEATURES_OK status bit
@@ -172,8 +308,9 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 			// issue a notify before all memory writes have finished. We will
 			// look at that later, but we need what is called a memory "fence"
 			// or barrier.
-			let queue_ptr = zalloc(num_pages) as *mut Queue;
-			let queue_pfn = queue_ptr as u32;
+			let (queue_virt, queue_phys) = ActiveHal::dma_alloc(num_pages);
+			let queue_ptr = queue_virt as *mut Queue;
+			let queue_pfn = queue_phys as u32;
 			// QueuePFN is a physical page number, however it appears for QEMU
 			// we have to write the entire memory address. This is a physical
 			// memory address where we (the OS) and the block device have
@@ -183,9 +320,42 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 			// We need to store all of this data as a "BlockDevice" structure
 			// We will be referring to this structure when making block requests
 			// AND when handling responses.
+            let mut desc_next = [0u16; VIRTIO_RING_SIZE];
+            for i in 0..VIRTIO_RING_SIZE {
+                desc_next[i] = (i + 1) as u16;
+            }
+            // Parse the fields of config space we actually care about. The
+            // capacity is always valid; blk_size and topology are only
+            // meaningful if their feature bits were negotiated, so fall
+            // back to the spec's 512-byte sector size otherwise.
+            let config_ptr = (ptr as *const u8).add(0x100) as *const Config;
+            let capacity = core::ptr::read_volatile(&(*config_ptr).capacity);
+            let blk_size = if guest_features & (1 << VIRTIO_BLK_F_BLK_SIZE) != 0 {
+                core::ptr::read_volatile(&(*config_ptr).blk_size)
+            } else {
+                512
+            };
+            let topology = if guest_features & (1 << VIRTIO_BLK_F_TOPOLOGY) != 0 {
+                core::ptr::read_volatile(&(*config_ptr).topology)
+            } else {
+                Topology { physical_block_exp: 0, alignment_offset: 0, min_io_size: 0, opt_io_size: 0 }
+            };
             let bd = BlockDevice { queue: queue_ptr,
                                    dev: ptr,
-			                       idx:   1, };
+			                       ack_used_idx: 0,
+			                       requests: [core::ptr::null_mut(); VIRTIO_RING_SIZE],
+			                       desc_next,
+			                       free_head: 0,
+			                       num_free: VIRTIO_RING_SIZE as u16,
+			                       desc_waiters: VecDeque::new(),
+			                       waiting: [NO_WAITER; VIRTIO_RING_SIZE],
+			                       results: [0u8; VIRTIO_RING_SIZE],
+			                       read_only,
+			                       features: guest_features,
+			                       config: config_ptr,
+			                       capacity,
+			                       blk_size,
+			                       topology, };
 			vdq.push_back(bd);
 
 			// Update the global block device array.
@@ -204,83 +374,543 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 	}
 }
 
-pub fn fill_next_descriptor(bd: &mut BlockDevice, desc: Descriptor) -> u16 {
+// Pop a descriptor off the free list. Callers must have reserved a
+// descriptor for themselves first (see `reserve_descs`); the assert is a
+// last-resort invariant check, not a backpressure mechanism, so a caller
+// that allocates without reserving is the bug.
+fn alloc_desc(bd: &mut BlockDevice) -> u16 {
+	assert!(bd.num_free > 0, "virtio-blk: descriptor ring exhausted despite reservation");
+	let idx = bd.free_head;
+	bd.free_head = bd.desc_next[idx as usize];
+	bd.num_free -= 1;
+	idx
+}
+
+// Return a descriptor to the free list, then wake one process parked in
+// `reserve_descs` if any are waiting.
+fn free_desc(bd: &mut BlockDevice, idx: u16) {
+	bd.desc_next[idx as usize] = bd.free_head;
+	bd.free_head = idx;
+	bd.num_free += 1;
+	if let Some(waiter) = bd.desc_waiters.pop_front() {
+		process::set_runnable(waiter);
+	}
+}
+
+// Block the calling process until `n` descriptors are free, so the three
+// `alloc_desc` calls a submit_* helper makes in a row can't run the ring
+// dry mid-chain. With a small VIRTIO_RING_SIZE and every request consuming
+// 3 descriptors, the ring saturates after just a couple of requests in
+// flight; without this, one request too many used to trip the assert in
+// `alloc_desc` and panic the kernel instead of just waiting its turn —
+// exactly the concurrent-I/O case descriptor accounting exists to make
+// safe.
+//
+// We re-check `num_free` every time we're scheduled again rather than
+// trusting the wakeup, since another submitter (or another parked waiter
+// woken first) may have already taken the descriptors we were waiting on.
+//
+// The check-then-park here has the same lost-wakeup hazard `block_on` has
+// to guard against: if the completion interrupt landed between us
+// pushing onto `desc_waiters` and actually calling `set_waiting`,
+// `free_desc` could pop us and call `set_runnable` before we've parked,
+// and our own `set_waiting` right after would stomp that and park us for
+// good. Interrupts stay off across the whole push-through-schedule
+// sequence to close it, the same as `block_on` does for completions.
+fn reserve_descs(dev: usize, n: u16) {
+	unsafe {
+		loop {
+			process::disable_interrupts();
+			let enough = if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+				let bdev = bdev_alloc.get_mut(dev).unwrap();
+				let enough = bdev.num_free >= n;
+				// Don't queue ourselves twice: a process that loops more
+				// than once (because someone else beat it to the freed
+				// descriptors) is still the same waiter, and a duplicate
+				// entry would hand free_desc a spurious wakeup for a pid
+				// that's already been satisfied instead of a genuinely
+				// distinct one.
+				if !enough && !bdev.desc_waiters.contains(&process::current_pid()) {
+					bdev.desc_waiters.push_back(process::current_pid());
+				}
+				BLOCK_DEVICES.replace(bdev_alloc);
+				enough
+			}
+			else {
+				// The block device list isn't available; let the caller's own
+				// BLOCK_DEVICES.take() fail the same way and report
+				// BlockError::IoError.
+				true
+			};
+			if enough {
+				process::enable_interrupts();
+				return;
+			}
+			process::set_waiting(process::current_pid());
+			process::schedule();
+			process::enable_interrupts();
+		}
+	}
+}
+
+// The device's capacity in 512-byte sectors, as reported in config space.
+pub fn capacity(dev: usize) -> u64 {
+	unsafe {
+		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			let capacity = bdev.capacity;
+			BLOCK_DEVICES.replace(bdev_alloc);
+			capacity
+		}
+		else {
+			0
+		}
+	}
+}
+
+// The device's block size in bytes, or 512 if the device never negotiated
+// VIRTIO_BLK_F_BLK_SIZE and therefore never reported one.
+pub fn block_size(dev: usize) -> u32 {
+	unsafe {
+		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			let blk_size = bdev.blk_size;
+			BLOCK_DEVICES.replace(bdev_alloc);
+			blk_size
+		}
+		else {
+			512
+		}
+	}
+}
+
+// The device's preferred I/O topology, or all-zero if it never negotiated
+// VIRTIO_BLK_F_TOPOLOGY and therefore never reported one.
+pub fn topology(dev: usize) -> Topology {
+	unsafe {
+		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			let topology = bdev.topology;
+			BLOCK_DEVICES.replace(bdev_alloc);
+			topology
+		}
+		else {
+			Topology { physical_block_exp: 0, alignment_offset: 0, min_io_size: 0, opt_io_size: 0 }
+		}
+	}
+}
+
+
+// This is the other half of the request/response cycle. The device raises
+// an interrupt whenever it has placed one or more completions on the used
+// ring; the MMIO interrupt handler should call this for the BlockDevice
+// that raised it. We ack the interrupt, then walk every used entry we
+// haven't seen yet, check its status, and free the Request that was
+// waiting on it.
+pub fn pending(bd: &mut BlockDevice) {
 	unsafe {
-		bd.idx = (bd.idx + 1) % VIRTIO_RING_SIZE as u16;
-		println!("idx = {}", bd.idx);
-		 (*bd.queue).desc[bd.idx as usize] = desc;
-		if (*bd.queue).desc[bd.idx as usize].flags & virtio::VIRTIO_DESC_F_NEXT != 0 {
-			(*bd.queue).desc[bd.idx as usize].next = (bd.idx + 1) % VIRTIO_RING_SIZE as u16;
+		// Tell the device we've seen the interrupt before we start looking
+		// at memory it wrote, otherwise we could race a second notification.
+		let interrupt_status = bd.dev.add(MmioOffsets::InterruptStatus.scale32()).read_volatile();
+		bd.dev.add(MmioOffsets::InterruptAck.scale32()).write_volatile(interrupt_status);
+		// The device updates used.idx (and the ring entries before it) with
+		// a regular store, so we need a fence to make sure we don't read a
+		// stale used.idx and then an even staler ring entry.
+		fence(Ordering::SeqCst);
+		while bd.ack_used_idx != (*bd.queue).used.idx {
+			let elem = &(*bd.queue).used.ring[(bd.ack_used_idx % VIRTIO_RING_SIZE as u16) as usize];
+			let head = elem.id as u16;
+			let request = bd.requests[head as usize];
+			if !request.is_null() {
+				bd.results[head as usize] = (*request).status.status;
+				match (*request).status.status {
+					VIRTIO_BLK_S_IOERR => println!("block: request {} failed with I/O error", head),
+					VIRTIO_BLK_S_UNSUPP => println!("block: request {} is unsupported by the device", head),
+					_ => (),
+				}
+				if (*request).owns_data {
+					kfree((*request).data.data);
+				}
+				kfree(request as *mut u8);
+				bd.requests[head as usize] = core::ptr::null_mut();
+			}
+			// Walk the descriptor chain this request used and hand every
+			// link back to the free list.
+			let mut desc_idx = head;
+			loop {
+				let desc = (*bd.queue).desc[desc_idx as usize];
+				let has_next = desc.flags & virtio::VIRTIO_DESC_F_NEXT != 0;
+				let next_idx = desc.next;
+				free_desc(bd, desc_idx);
+				if !has_next {
+					break;
+				}
+				desc_idx = next_idx;
+			}
+			// If a process blocked on this request, wake it up now that its
+			// result is waiting in bd.results.
+			let waiter = bd.waiting[head as usize];
+			if waiter != NO_WAITER {
+				process::set_runnable(waiter);
+				bd.waiting[head as usize] = NO_WAITER;
+			}
+			bd.ack_used_idx = bd.ack_used_idx.wrapping_add(1);
+		}
+	}
+}
+
+// Block the calling process until the request submitted under `head_idx`
+// completes, then hand back the status the reaper stashed for it. Callers
+// that can't afford to block can use the `_async` variants instead, which
+// return the head index without waiting for the request to complete.
+//
+// The caller must already be registered as the waiter for `head_idx` (the
+// submit_* helpers do this atomically with ringing the doorbell, before
+// this is ever called) so that a completion racing ahead of us can't be
+// lost. Even so, the reaper may have already run by the time we get here —
+// `requests[head_idx]` is nulled out the instant a completion is reaped, so
+// we check that before parking instead of blindly sleeping on a result
+// that already arrived.
+//
+// That check-then-park is itself a window the completion interrupt can
+// land in: if it fires between us seeing `already_done == false` and us
+// actually calling `set_waiting`, `pending` will call `set_runnable` on a
+// process that hasn't parked yet, and our own `set_waiting` right after
+// would stomp that runnable state and park us for good (the reaper has
+// already cleared `requests`/`waiting` for this slot, so nothing would
+// ever wake us again). Interrupts stay off across the whole
+// check-through-schedule sequence to close it; `schedule` hands them back
+// to the next process it switches to, and we get them back ourselves once
+// we're resumed.
+fn block_on(dev: usize, head_idx: u16) -> Result<(), u8> {
+	unsafe {
+		process::disable_interrupts();
+		let already_done = if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			let done = bdev.requests[head_idx as usize].is_null();
+			BLOCK_DEVICES.replace(bdev_alloc);
+			done
+		}
+		else {
+			false
+		};
+		if !already_done {
+			process::set_waiting(process::current_pid());
+			process::schedule();
+		}
+		process::enable_interrupts();
+		// We're only resumed after the completion reaper has woken us (or we
+		// never parked because it already had), both of which only happen
+		// once bd.results[head_idx] holds our status.
+		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			let status = bdev.results[head_idx as usize];
+			BLOCK_DEVICES.replace(bdev_alloc);
+			match status {
+				VIRTIO_BLK_S_OK => Ok(()),
+				err => Err(err),
+			}
+		}
+		else {
+			Err(VIRTIO_BLK_S_IOERR)
 		}
-		bd.idx
 	}
 }
 
+// Blocking read: submits the request and parks the calling process until
+// the device signals completion.
+pub fn read(dev: usize, buffer: *mut u8, size: u32, offset: usize) -> Result<(), BlockError> {
+	let head_idx = submit_read(dev, buffer, size, offset, process::current_pid())?;
+	block_on(dev, head_idx).map_err(BlockError::from_status)
+}
+
+// Submit a read and return immediately with the head descriptor index.
+// No process is registered to be woken: `pending` reaps and frees the
+// request once the device completes it, and its status is not reported
+// back through this API.
+pub fn read_async(dev: usize, buffer: *mut u8, size: u32, offset: usize) -> Result<u16, BlockError> {
+	submit_read(dev, buffer, size, offset, NO_WAITER)
+}
 
-pub fn read(dev: usize, buffer: *mut u8, size: u32, offset: usize) {
+// Shared by read/read_async: builds and submits the descriptor chain for a
+// read, recording `waiter` (a pid, or NO_WAITER for async callers nobody is
+// blocked on) before the doorbell is rung. Registering the waiter as part
+// of submission, rather than after, closes the window where the device
+// could complete the request and raise its interrupt before anyone had
+// recorded who to wake.
+fn submit_read(dev: usize, buffer: *mut u8, size: u32, offset: usize, waiter: u16) -> Result<u16, BlockError> {
 	unsafe {
+		// Reserve the whole chain's worth of descriptors before touching
+		// the ring, so the three allocations below can't run it dry.
+		reserve_descs(dev, 3);
 		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
 			let bdev = bdev_alloc.get_mut(dev).unwrap();
 			let sector = offset / 512;
 			let blk_request_size = size_of::<Request>();
 			let blk_request = kmalloc(blk_request_size) as *mut Request;
-			let desc = Descriptor {
-				addr: &(*blk_request).header as *const Header as u64,
-				len: blk_request_size as u32,
-				flags: virtio::VIRTIO_DESC_F_NEXT,
-				next: 0,
-			};
-			let head_idx = fill_next_descriptor(bdev, desc);
 			(*blk_request).header.sector = sector as u64;
 			(*blk_request).header.blktype = VIRTIO_BLK_T_IN;
 			(*blk_request).data.data = buffer;
-			let desc = Descriptor {
-				addr: buffer as u64,
+			(*blk_request).owns_data = false;
+			// Allocate the whole chain up front so each descriptor's `next`
+			// can point at the real index of the one after it, rather than
+			// assuming the free list hands out consecutive slots.
+			let head_idx = alloc_desc(bdev);
+			let data_idx = alloc_desc(bdev);
+			let status_idx = alloc_desc(bdev);
+			(*bdev.queue).desc[head_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(&(*blk_request).header as *const Header as *const u8) as u64,
+				len: blk_request_size as u32,
+				flags: virtio::VIRTIO_DESC_F_NEXT,
+				next: data_idx,
+			};
+			(*bdev.queue).desc[data_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(buffer as *const u8) as u64,
 				len: size,
 				flags: virtio::VIRTIO_DESC_F_NEXT | virtio::VIRTIO_DESC_F_WRITE,
-				next: 0,
+				next: status_idx,
 			};
-			let data_idx = fill_next_descriptor(bdev, desc);
-			let desc = Descriptor {
-				addr: &(*blk_request).status as *const Status as u64,
+			(*bdev.queue).desc[status_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(&(*blk_request).status as *const Status as *const u8) as u64,
 				len: size_of::<Status>() as u32,
 				flags: virtio::VIRTIO_DESC_F_WRITE,
 				next: 0,
 			};
-			let status_idx = fill_next_descriptor(bdev, desc);
+			bdev.requests[head_idx as usize] = blk_request;
+			bdev.waiting[head_idx as usize] = waiter;
 			(*bdev.queue).avail.ring[(*bdev.queue).avail.idx as usize] = head_idx;
 			println!("Avail at {}, set head to {}", (*bdev.queue).avail.idx, head_idx);
 			(*bdev.queue).avail.idx = ((*bdev.queue).avail.idx + 1) % virtio::VIRTIO_RING_SIZE as u16;
 			bdev.dev.add(MmioOffsets::QueueNotify.scale32()).write_volatile(0);
+			BLOCK_DEVICES.replace(bdev_alloc);
+			Ok(head_idx)
+		}
+		else {
+			Err(BlockError::IoError)
 		}
 	}
 }
-pub fn write(dev: usize, buffer: *const u8, size: usize, offset: usize) {
+
+// Blocking write: submits the request and parks the calling process until
+// the device signals completion.
+pub fn write(dev: usize, buffer: *const u8, size: usize, offset: usize) -> Result<(), BlockError> {
+	let head_idx = submit_write(dev, buffer, size, offset, process::current_pid())?;
+	block_on(dev, head_idx).map_err(BlockError::from_status)
+}
+
+// Submit a write and return immediately with the head descriptor index.
+// No process is registered to be woken: `pending` reaps and frees the
+// request once the device completes it, and its status is not reported
+// back through this API.
+pub fn write_async(dev: usize, buffer: *const u8, size: usize, offset: usize) -> Result<u16, BlockError> {
+	submit_write(dev, buffer, size, offset, NO_WAITER)
+}
+
+// Shared by write/write_async: builds and submits the descriptor chain for
+// a write, recording `waiter` (a pid, or NO_WAITER for async callers nobody
+// is blocked on) before the doorbell is rung. Registering the waiter as
+// part of submission, rather than after, closes the window where the
+// device could complete the request and raise its interrupt before anyone
+// had recorded who to wake. Mirrors submit_read: a header descriptor, a
+// data descriptor pointing at the caller's buffer, and a status descriptor
+// the device writes into. Unlike a read, the data descriptor carries no
+// VIRTIO_DESC_F_WRITE flag, since the device is reading guest memory
+// rather than filling it.
+fn submit_write(dev: usize, buffer: *const u8, size: usize, offset: usize, waiter: u16) -> Result<u16, BlockError> {
 	unsafe {
+		// Reject a read-only device before reserving descriptors, so a
+		// doomed write doesn't park its caller waiting for a ring that was
+		// never going to free up on its account anyway.
+		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			let read_only = bdev.read_only;
+			BLOCK_DEVICES.replace(bdev_alloc);
+			if read_only {
+				return Err(BlockError::ReadOnly);
+			}
+		}
+		reserve_descs(dev, 3);
 		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
 			let bdev = bdev_alloc.get_mut(dev).unwrap();
 			let sector = offset / 512;
-			let desc = Descriptor {
-				addr: 0,
-				len: 0,
-				flags: 0,
-				next: 0,
+			let blk_request_size = size_of::<Request>();
+			let blk_request = kmalloc(blk_request_size) as *mut Request;
+			(*blk_request).header.sector = sector as u64;
+			(*blk_request).header.blktype = VIRTIO_BLK_T_OUT;
+			(*blk_request).data.data = buffer as *mut u8;
+			(*blk_request).owns_data = false;
+			// Allocate the whole chain up front, same as submit_read, so
+			// each descriptor's `next` names a real index.
+			let head_idx = alloc_desc(bdev);
+			let data_idx = alloc_desc(bdev);
+			let status_idx = alloc_desc(bdev);
+			(*bdev.queue).desc[head_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(&(*blk_request).header as *const Header as *const u8) as u64,
+				len: blk_request_size as u32,
+				flags: virtio::VIRTIO_DESC_F_NEXT,
+				next: data_idx,
 			};
-			let head_idx = fill_next_descriptor(bdev, desc);
-			let desc = Descriptor {
-				addr: 0,
-				len: 0,
-				flags: 0,
+			(*bdev.queue).desc[data_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(buffer as *const u8) as u64,
+				len: size as u32,
+				flags: virtio::VIRTIO_DESC_F_NEXT,
+				next: status_idx,
+			};
+			(*bdev.queue).desc[status_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(&(*blk_request).status as *const Status as *const u8) as u64,
+				len: size_of::<Status>() as u32,
+				flags: virtio::VIRTIO_DESC_F_WRITE,
 				next: 0,
 			};
-			let data_idx = fill_next_descriptor(bdev, desc);
-			let desc = Descriptor {
-				addr: 0,
-				len: 0,
-				flags: 0,
+			bdev.requests[head_idx as usize] = blk_request;
+			bdev.waiting[head_idx as usize] = waiter;
+			(*bdev.queue).avail.ring[(*bdev.queue).avail.idx as usize] = head_idx;
+			(*bdev.queue).avail.idx = ((*bdev.queue).avail.idx + 1) % virtio::VIRTIO_RING_SIZE as u16;
+			bdev.dev.add(MmioOffsets::QueueNotify.scale32()).write_volatile(0);
+			BLOCK_DEVICES.replace(bdev_alloc);
+			Ok(head_idx)
+		}
+		else {
+			Err(BlockError::IoError)
+		}
+	}
+}
+
+// Submit a single discard/write-zeroes segment and block until it
+// completes. `blktype` is VIRTIO_BLK_T_DISCARD or VIRTIO_BLK_T_WRITE_ZEROES;
+// `seg_flags` is the flags word of the segment itself (only the unmap bit
+// is defined today). We only ever put one segment in a request, so
+// max_discard_seg/max_write_zeroes_seg (which bound segments per request,
+// not sectors) are trivially satisfied.
+fn submit_discard_write_zeroes(dev: usize, blktype: u32, sector: u64, num_sectors: u32, seg_flags: u32) -> Result<(), BlockError> {
+	unsafe {
+		reserve_descs(dev, 3);
+		let head_idx;
+		if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			let blk_request_size = size_of::<Request>();
+			let blk_request = kmalloc(blk_request_size) as *mut Request;
+			(*blk_request).header.blktype = blktype;
+			(*blk_request).header.sector = 0;
+			let seg_size = size_of::<DiscardWriteZeroes>();
+			let segment = kmalloc(seg_size) as *mut DiscardWriteZeroes;
+			(*segment).sector = sector;
+			(*segment).num_sectors = num_sectors;
+			(*segment).flags = seg_flags;
+			(*blk_request).data.data = segment as *mut u8;
+			(*blk_request).owns_data = true;
+			let head = alloc_desc(bdev);
+			let data_idx = alloc_desc(bdev);
+			let status_idx = alloc_desc(bdev);
+			(*bdev.queue).desc[head as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(&(*blk_request).header as *const Header as *const u8) as u64,
+				len: blk_request_size as u32,
+				flags: virtio::VIRTIO_DESC_F_NEXT,
+				next: data_idx,
+			};
+			(*bdev.queue).desc[data_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(segment as *const u8) as u64,
+				len: seg_size as u32,
+				flags: virtio::VIRTIO_DESC_F_NEXT,
+				next: status_idx,
+			};
+			(*bdev.queue).desc[status_idx as usize] = Descriptor {
+				addr: ActiveHal::virt_to_phys(&(*blk_request).status as *const Status as *const u8) as u64,
+				len: size_of::<Status>() as u32,
+				flags: virtio::VIRTIO_DESC_F_WRITE,
 				next: 0,
 			};
-			let status_idx = fill_next_descriptor(bdev, desc);
+			bdev.requests[head as usize] = blk_request;
+			// We always block on this request (there's no discard_async/
+			// write_zeroes_async), so register ourselves as the waiter
+			// before ringing the doorbell — otherwise a device that
+			// completes the request before block_on gets a chance to run
+			// would raise its interrupt against NO_WAITER and nobody would
+			// ever be woken.
+			bdev.waiting[head as usize] = process::current_pid();
+			(*bdev.queue).avail.ring[(*bdev.queue).avail.idx as usize] = head;
+			(*bdev.queue).avail.idx = ((*bdev.queue).avail.idx + 1) % virtio::VIRTIO_RING_SIZE as u16;
+			bdev.dev.add(MmioOffsets::QueueNotify.scale32()).write_volatile(0);
+			BLOCK_DEVICES.replace(bdev_alloc);
+			head_idx = head;
+		}
+		else {
+			return Err(BlockError::IoError);
+		}
+		block_on(dev, head_idx).map_err(BlockError::from_status)
+	}
+}
+
+// Discard `num_sectors` sectors starting at `start_sector`, telling the
+// device the data there is no longer needed. Refused on a read-only
+// device, the same as write. Only sent if the device negotiated
+// VIRTIO_BLK_F_DISCARD; the request is split into chunks no larger than
+// the device's advertised max_discard_sector.
+pub fn discard(dev: usize, start_sector: u64, num_sectors: u64) -> Result<(), BlockError> {
+	unsafe {
+		let chunk = if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			if bdev.read_only {
+				BLOCK_DEVICES.replace(bdev_alloc);
+				return Err(BlockError::ReadOnly);
+			}
+			if bdev.features & (1 << VIRTIO_BLK_F_DISCARD) == 0 {
+				BLOCK_DEVICES.replace(bdev_alloc);
+				return Err(BlockError::Unsupported);
+			}
+			let max_sectors = core::ptr::read_volatile(&(*bdev.config).max_discard_sector);
+			BLOCK_DEVICES.replace(bdev_alloc);
+			if max_sectors == 0 { num_sectors } else { max_sectors as u64 }
+		}
+		else {
+			return Err(BlockError::IoError);
+		};
+		let mut sector = start_sector;
+		let mut remaining = num_sectors;
+		while remaining > 0 {
+			let this_chunk = remaining.min(chunk);
+			submit_discard_write_zeroes(dev, VIRTIO_BLK_T_DISCARD, sector, this_chunk as u32, 0)?;
+			sector += this_chunk;
+			remaining -= this_chunk;
+		}
+		Ok(())
+	}
+}
+
+// Zero `num_sectors` sectors starting at `start_sector` without actually
+// writing zero buffers over the wire. If `unmap` is set, the device is
+// additionally allowed to discard the backing blocks instead of writing
+// zeroes to them. Refused on a read-only device, the same as write. Only
+// sent if the device negotiated VIRTIO_BLK_F_WRITE_ZEROES; split into
+// chunks no larger than the device's advertised max_write_zeroes_sectors.
+pub fn write_zeroes(dev: usize, start_sector: u64, num_sectors: u64, unmap: bool) -> Result<(), BlockError> {
+	unsafe {
+		let chunk = if let Some(mut bdev_alloc) = BLOCK_DEVICES.take() {
+			let bdev = bdev_alloc.get_mut(dev).unwrap();
+			if bdev.read_only {
+				BLOCK_DEVICES.replace(bdev_alloc);
+				return Err(BlockError::ReadOnly);
+			}
+			if bdev.features & (1 << VIRTIO_BLK_F_WRITE_ZEROES) == 0 {
+				BLOCK_DEVICES.replace(bdev_alloc);
+				return Err(BlockError::Unsupported);
+			}
+			let max_sectors = core::ptr::read_volatile(&(*bdev.config).max_write_zeroes_sectors);
+			BLOCK_DEVICES.replace(bdev_alloc);
+			if max_sectors == 0 { num_sectors } else { max_sectors as u64 }
+		}
+		else {
+			return Err(BlockError::IoError);
+		};
+		let seg_flags = if unmap { VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP } else { 0 };
+		let mut sector = start_sector;
+		let mut remaining = num_sectors;
+		while remaining > 0 {
+			let this_chunk = remaining.min(chunk);
+			submit_discard_write_zeroes(dev, VIRTIO_BLK_T_WRITE_ZEROES, sector, this_chunk as u32, seg_flags)?;
+			sector += this_chunk;
+			remaining -= this_chunk;
 		}
+		Ok(())
 	}
 }
\ No newline at end of file